@@ -2,6 +2,7 @@ use ijson::IString;
 use log::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::{
     collections::HashMap,
     env,
@@ -10,6 +11,7 @@ use std::{
     io::{BufReader, Read, Write},
     path::Path,
     process::Command,
+    time::Duration,
 };
 
 use crate::ui::window::{SystemPkgs, UserPkgs};
@@ -26,38 +28,389 @@ struct NewPackage {
     version: IString,
 }
 
+// A unit of work checkcache can hand to runparallel.
+type CacheTask = Box<dyn FnOnce() -> Result<(), Box<dyn Error + Send + Sync>> + Send>;
+
 pub fn checkcache(
     syspkgs: SystemPkgs,
     userpkgs: UserPkgs,
     config: NscConfig,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut tasks: Vec<CacheTask> = Vec::new();
+
     match syspkgs {
         SystemPkgs::Legacy => {
-            setuplegacypkgscache()?;
-            setupupdatecache()?;
-            setupnewestver()?;
+            tasks.push(Box::new(setuplegacypkgscache));
+            tasks.push(Box::new(setupupdatecache));
+            tasks.push(Box::new(setupnewestver));
         }
         SystemPkgs::Flake => {
+            // Run this one synchronously rather than handing it to runparallel:
+            // it shares syspackages.json/newver.txt with setupupdatecache and
+            // setupnewestver below, which still run whenever userpkgs == Env,
+            // and those writes aren't safe to interleave with this one's.
             setupflakepkgscache(config)?;
         }
         SystemPkgs::None => {
             if userpkgs == UserPkgs::Profile {
-                getlatestpkgs().unwrap();
+                tasks.push(Box::new(getlatestpkgs));
             }
         }
     }
 
     if userpkgs == UserPkgs::Env && syspkgs != SystemPkgs::Legacy {
-        setupupdatecache()?;
-        setupnewestver()?;
+        tasks.push(Box::new(setupupdatecache));
+        tasks.push(Box::new(setupnewestver));
     }
 
     if userpkgs == UserPkgs::Profile {
-        setupprofilepkgscache()?;
+        tasks.push(Box::new(setupprofilepkgscache));
+    }
+
+    runparallel(tasks)?;
+    Ok(())
+}
+
+// Run independent cache-setup jobs concurrently, collecting every task's
+// error instead of aborting on the first one.
+fn runparallel(tasks: Vec<CacheTask>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut errors = Vec::new();
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = tasks.into_iter().map(|task| scope.spawn(task)).collect();
+        for handle in handles {
+            match handle.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => errors.push(e.to_string()),
+                Err(_) => errors.push("cache worker thread panicked".to_string()),
+            }
+        }
+    });
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; ").into())
+    }
+}
+
+// setuplegacypkgscache and setupprofilepkgscache can now run concurrently
+// (see checkcache), and both remove the same legacy chnver.txt.
+// A plain exists()-then-remove_file() is a TOCTOU race between them, so
+// treat "someone else already removed it" as success instead of erroring.
+fn removeifexists(path: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+// The generated package listings `checkcache` can produce, plus
+// narinfocache.json (substituter-availability results).
+const CACHEFILES: [&str; 4] = [
+    "packages.json",
+    "syspackages.json",
+    "profilepackages.json",
+    "narinfocache.json",
+];
+
+// The `*ver.txt` sentinels `checkcache` uses to decide whether a cache is stale.
+const VERSIONSENTINELS: [&str; 5] = [
+    "sysver.txt",
+    "chnver.txt",
+    "newver.txt",
+    "flakever.txt",
+    "profilever.txt",
+];
+
+// On-disk presence and size of one of the CACHEFILES.
+#[derive(Debug, Clone)]
+pub struct CacheFileStatus {
+    pub name: String,
+    pub exists: bool,
+    pub sizebytes: u64,
+}
+
+// Snapshot of the `~/.cache/nix-software-center` state.
+#[derive(Debug, Clone)]
+pub struct CacheStatus {
+    pub caches: Vec<CacheFileStatus>,
+    pub versions: HashMap<String, String>,
+}
+
+pub fn clearcache() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let cachedir = format!("{}/.cache/nix-software-center", env::var("HOME")?);
+    for name in CACHEFILES.iter().chain(VERSIONSENTINELS.iter()) {
+        removeifexists(&format!("{}/{}", cachedir, name))?;
+    }
+    Ok(())
+}
+
+// Deleting only the version sentinels isn't enough to force a redownload:
+// setupupdatecache recreates the sentinel with the freshly-queried current
+// version before checking it against the data file, so if the upstream
+// version hasn't changed it short-circuits without ever
+// calling dlfile. Remove the data files too, same as clearcache.
+pub fn forcerefresh(
+    syspkgs: SystemPkgs,
+    userpkgs: UserPkgs,
+    config: NscConfig,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let cachedir = format!("{}/.cache/nix-software-center", env::var("HOME")?);
+    for name in CACHEFILES.iter().chain(VERSIONSENTINELS.iter()) {
+        removeifexists(&format!("{}/{}", cachedir, name))?;
+    }
+    checkcache(syspkgs, userpkgs, config)
+}
+
+pub fn cachestatus() -> Result<CacheStatus, Box<dyn Error>> {
+    let cachedir = format!("{}/.cache/nix-software-center", env::var("HOME")?);
+
+    let mut caches = Vec::new();
+    for name in CACHEFILES {
+        let path = format!("{}/{}", cachedir, name);
+        let exists = Path::new(&path).exists();
+        let sizebytes = if exists { fs::metadata(&path)?.len() } else { 0 };
+        caches.push(CacheFileStatus {
+            name: name.to_string(),
+            exists,
+            sizebytes,
+        });
     }
+
+    let mut versions = HashMap::new();
+    for name in VERSIONSENTINELS {
+        let path = format!("{}/{}", cachedir, name);
+        if let Ok(value) = fs::read_to_string(&path) {
+            versions.insert(
+                name.trim_end_matches(".txt").to_string(),
+                value.trim().to_string(),
+            );
+        }
+    }
+
+    Ok(CacheStatus { caches, versions })
+}
+
+// How long a `narinfocache.json` entry is trusted before it is re-checked.
+const NARINFO_CACHE_TTL_SECS: u64 = 15 * 60;
+
+const DEFAULT_SUBSTITUTER: &str = "https://cache.nixos.org";
+
+// How many attributes to check against the substituter at once: each check
+// spawns nix-instantiate and nix-store subprocesses, and a visible
+// package list can run into the hundreds, so fan-out is batched rather than
+// unbounded.
+const SUBSTITUTER_CHECK_CONCURRENCY: usize = 8;
+
+// Whether a package's derivation output is fetchable from a binary cache,
+// or would have to be built from source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SubstituterAvailability {
+    Cached,
+    BuildsFromSource,
+    Unknown,
+}
+
+// narinfocache.json is keyed by attribute name rather than by the store-path
+// hash cache.nixos.org actually indexes narinfo by. Keying by hash would
+// mean running nix-instantiate/nix-store just to find the cache key, which
+// defeats the point of caching (that pair of subprocesses is the expensive
+// part substituteravailability's TTL exists to avoid paying on every call). The
+// trade-off: if an attribute's output path changes within
+// NARINFO_CACHE_TTL_SECS (a routine nixpkgs bump), the old hash's
+// availability is served under the attribute's key with no hash check at
+// all until the entry expires. Acceptable here since availability rarely
+// flips within a 15-minute window and this is advisory UI, not a build input.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct NarinfoCacheEntry {
+    availability: SubstituterAvailability,
+    checkedat: u64,
+}
+
+// One attribute's substituter-lookup outcome, as produced by a batch of
+// concurrent checksubstituter workers.
+type SubstituterLookup = (String, Result<SubstituterAvailability, Box<dyn Error + Send + Sync>>);
+
+fn narinfocachepath() -> Result<String, Box<dyn Error + Send + Sync>> {
+    Ok(format!(
+        "{}/.cache/nix-software-center/narinfocache.json",
+        env::var("HOME")?
+    ))
+}
+
+fn loadnarinfocache() -> HashMap<String, NarinfoCacheEntry> {
+    narinfocachepath()
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|raw| serde_json::from_str(&raw).ok())
+        .unwrap_or_default()
+}
+
+fn savenarinfocache(
+    cache: &HashMap<String, NarinfoCacheEntry>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let cachedir = format!("{}/.cache/nix-software-center", env::var("HOME")?);
+    fs::create_dir_all(&cachedir)?;
+    fs::write(narinfocachepath()?, serde_json::to_string(cache)?)?;
     Ok(())
 }
 
+fn unixnow() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Instantiates `attr`'s derivation and returns its output store path's
+// 32-char hash component.
+fn storepathhash(attr: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let instantiated = Command::new("nix-instantiate")
+        .arg("<nixpkgs>")
+        .arg("-A")
+        .arg(attr)
+        .output()?;
+    let drvpath = String::from_utf8_lossy(&instantiated.stdout)
+        .trim()
+        .to_string();
+    if drvpath.is_empty() {
+        return Err(format!("Failed to instantiate derivation for {}", attr).into());
+    }
+
+    // Note: no `--derivation` here — that flag tells `path-info` to report on
+    // the .drv itself, which would hash the derivation rather than the output
+    // path cache.nixos.org actually indexes narinfo by.
+    let pathinfo = Command::new("nix-store")
+        .arg("-q")
+        .arg("--outputs")
+        .arg(&drvpath)
+        .output()?;
+    let outpath = String::from_utf8_lossy(&pathinfo.stdout)
+        .lines()
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_string();
+
+    let filename = Path::new(&outpath)
+        .file_name()
+        .and_then(|f| f.to_str())
+        .ok_or_else(|| format!("Could not parse store path for {}", attr))?;
+    let hash = filename.split('-').next().unwrap_or(filename);
+    if hash.len() != 32 {
+        return Err(format!("Unexpected store path hash for {}: {}", attr, hash).into());
+    }
+    Ok(hash.to_string())
+}
+
+// Checks a single store-path hash against a substituter's narinfo endpoint:
+// a 200 means a prebuilt binary is substitutable, a 404 means it would
+// build from source.
+fn checksubstituter(
+    hash: &str,
+    substituterurl: &str,
+) -> Result<SubstituterAvailability, Box<dyn Error + Send + Sync>> {
+    let url = format!("{}/{}.narinfo", substituterurl.trim_end_matches('/'), hash);
+    let response = reqwest::blocking::Client::new().head(&url).send()?;
+    match response.status() {
+        s if s.is_success() => Ok(SubstituterAvailability::Cached),
+        reqwest::StatusCode::NOT_FOUND => Ok(SubstituterAvailability::BuildsFromSource),
+        s => Err(format!("Unexpected narinfo response for {}: {}", hash, s).into()),
+    }
+}
+
+// Splits `attrs` into those whose narinfocache.json entry is still within
+// NARINFO_CACHE_TTL_SECS of `now` (returned directly) and those that need a
+// fresh substituter lookup (returned for the caller to probe).
+fn partitionbyfreshness(
+    attrs: &[String],
+    cache: &HashMap<String, NarinfoCacheEntry>,
+    now: u64,
+) -> (HashMap<String, SubstituterAvailability>, Vec<String>) {
+    let mut results = HashMap::new();
+    let mut tolookup = Vec::new();
+    for attr in attrs {
+        if let Some(entry) = cache.get(attr) {
+            if now.saturating_sub(entry.checkedat) < NARINFO_CACHE_TTL_SECS {
+                results.insert(attr.clone(), entry.availability);
+                continue;
+            }
+        }
+        tolookup.push(attr.clone());
+    }
+    (results, tolookup)
+}
+
+// Annotates every attribute in `attrs` with its substituter availability,
+// checking at most SUBSTITUTER_CHECK_CONCURRENCY attributes at once and
+// caching results in narinfocache.json for NARINFO_CACHE_TTL_SECS so
+// re-listing the same packages doesn't re-probe the binary cache.
+// `substituterurl` defaults to `https://cache.nixos.org`.
+pub fn substituteravailability(
+    attrs: &[String],
+    substituterurl: Option<&str>,
+) -> Result<HashMap<String, SubstituterAvailability>, Box<dyn Error>> {
+    let substituterurl = substituterurl.unwrap_or(DEFAULT_SUBSTITUTER).to_string();
+    let mut cache = loadnarinfocache();
+    let now = unixnow();
+
+    let (mut results, tolookup) = partitionbyfreshness(attrs, &cache, now);
+
+    if !tolookup.is_empty() {
+        for chunk in tolookup.chunks(SUBSTITUTER_CHECK_CONCURRENCY) {
+            let pairs: Vec<SubstituterLookup> = std::thread::scope(|scope| {
+                let handles: Vec<_> = chunk
+                    .iter()
+                    .cloned()
+                    .map(|attr| {
+                        let substituterurl = substituterurl.clone();
+                        let key = attr.clone();
+                        let handle = scope.spawn(move || {
+                            storepathhash(&attr)
+                                .and_then(|hash| checksubstituter(&hash, &substituterurl))
+                        });
+                        (key, handle)
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|(attr, handle)| {
+                        let result = handle
+                            .join()
+                            .unwrap_or_else(|_| Err("substituter worker thread panicked".into()));
+                        (attr, result)
+                    })
+                    .collect()
+            });
+
+            for (attr, lookup) in pairs {
+                match lookup {
+                    Ok(availability) => {
+                        cache.insert(
+                            attr.clone(),
+                            NarinfoCacheEntry {
+                                availability,
+                                checkedat: now,
+                            },
+                        );
+                        results.insert(attr, availability);
+                    }
+                    Err(e) => {
+                        warn!("Substituter check failed for {}: {}", attr, e);
+                        results.insert(attr, SubstituterAvailability::Unknown);
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = savenarinfocache(&cache) {
+            warn!("Failed to persist narinfocache.json: {}", e);
+        }
+    }
+
+    Ok(results)
+}
+
 pub fn uptodatelegacy() -> Result<Option<(String, String)>, Box<dyn Error>> {
     let cachedir = format!("{}/.cache/nix-software-center", env::var("HOME")?);
     let oldversion = fs::read_to_string(format!("{}/sysver.txt", cachedir))?
@@ -129,7 +482,7 @@ pub fn flakever() -> Result<Option<(String, String)>, Box<dyn Error>> {
     }
 }
 
-fn getlatestpkgs() -> Result<(), Box<dyn Error>> {
+fn getlatestpkgs() -> Result<(), Box<dyn Error + Send + Sync>> {
     let vout = Command::new("nixos-version").arg("--json").output()?;
 
     let versiondata: Value = serde_json::from_str(&String::from_utf8_lossy(&vout.stdout))?;
@@ -177,7 +530,7 @@ fn getlatestpkgs() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn setuplegacypkgscache() -> Result<(), Box<dyn Error>> {
+fn setuplegacypkgscache() -> Result<(), Box<dyn Error + Send + Sync>> {
     info!("Setting up legacy package cache");
     let vout = Command::new("nix-instantiate")
         .arg("-I")
@@ -216,40 +569,37 @@ fn setuplegacypkgscache() -> Result<(), Box<dyn Error>> {
         fs::create_dir_all(&cachedir).expect("Failed to create cache directory");
     }
 
-    if !Path::new(&format!("{}/chnver.txt", &cachedir)).exists() {
-        let mut sysver = fs::File::create(format!("{}/chnver.txt", &cachedir))?;
-        sysver.write_all(dlver.as_bytes())?;
-    }
+    // setupprofilepkgscache can run concurrently with this function (see
+    // checkcache) and unconditionally removes this same legacy chnver.txt at
+    // its start, so a plain exists()-then-read_to_string() here would be the
+    // same TOCTOU race removeifexists was added for: treat a chnver.txt that
+    // vanishes mid-check as simply stale rather than propagating NotFound.
+    let chnverpath = format!("{}/chnver.txt", &cachedir);
+    let cached = fs::read_to_string(&chnverpath).ok();
+    let uptodate = cached.as_deref().map(str::trim) == Some(dlver.as_str())
+        && Path::new(&format!("{}/packages.json", &cachedir)).exists();
 
-    if Path::new(format!("{}/chnver.txt", &cachedir).as_str()).exists()
-        && fs::read_to_string(&Path::new(format!("{}/chnver.txt", &cachedir).as_str()))?.trim()
-            == dlver
-        && Path::new(format!("{}/packages.json", &cachedir).as_str()).exists()
-    {
+    if uptodate {
         return Ok(());
-    } else {
-        let oldver = fs::read_to_string(&Path::new(format!("{}/chnver.txt", &cachedir).as_str()))?;
-        let sysver = &dlver;
-        info!("OLD: {}, != NEW: {}", oldver.trim(), sysver.trim());
     }
-    if Path::new(format!("{}/chnver.txt", &cachedir).as_str()).exists() {
-        fs::remove_file(format!("{}/chnver.txt", &cachedir).as_str())?;
+    if let Some(oldver) = &cached {
+        info!("OLD: {}, != NEW: {}", oldver.trim(), dlver.trim());
     }
-    let mut sysver = fs::File::create(format!("{}/chnver.txt", &cachedir))?;
+
+    removeifexists(&chnverpath)?;
+    let mut sysver = fs::File::create(&chnverpath)?;
     sysver.write_all(dlver.as_bytes())?;
     let outfile = format!("{}/packages.json", &cachedir);
     dlfile(&url, &outfile)?;
     Ok(())
 }
 
-fn setupflakepkgscache(config: NscConfig) -> Result<(), Box<dyn Error>> {
+fn setupflakepkgscache(config: NscConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
     info!("Setting up flake cache");
     let cachedir = format!("{}/.cache/nix-software-center", env::var("HOME")?);
 
     // First remove legacy files
-    if Path::new(format!("{}/chnver.txt", &cachedir).as_str()).exists() {
-        fs::remove_file(format!("{}/chnver.txt", &cachedir).as_str())?;
-    }
+    removeifexists(format!("{}/chnver.txt", &cachedir).as_str())?;
 
     let vout = Command::new("nixos-version").arg("--json").output()?;
 
@@ -272,7 +622,7 @@ fn setupflakepkgscache(config: NscConfig) -> Result<(), Box<dyn Error>> {
         relver.trim()
     );
 
-    fn writesyspkgs(outfile: &str, inputpath: &str) -> Result<(), Box<dyn Error>> {
+    fn writesyspkgs(outfile: &str, inputpath: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
         let output = Command::new("nix")
             .arg("search")
             .arg("--inputs-from")
@@ -339,14 +689,12 @@ fn setupflakepkgscache(config: NscConfig) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn setupprofilepkgscache() -> Result<(), Box<dyn Error>> {
+fn setupprofilepkgscache() -> Result<(), Box<dyn Error + Send + Sync>> {
     info!("Setting up profile package cache");
     let cachedir = format!("{}/.cache/nix-software-center", env::var("HOME")?);
 
     // First remove legacy files
-    if Path::new(format!("{}/chnver.txt", &cachedir).as_str()).exists() {
-        fs::remove_file(format!("{}/chnver.txt", &cachedir).as_str())?;
-    }
+    removeifexists(format!("{}/chnver.txt", &cachedir).as_str())?;
 
     fs::create_dir_all(&cachedir).expect("Failed to create cache directory");
     let url = "https://channels.nixos.org/nixpkgs-unstable/packages.json.br".to_string();
@@ -387,7 +735,7 @@ fn setupprofilepkgscache() -> Result<(), Box<dyn Error>> {
 
 // nix-instantiate --eval -E '(builtins.getFlake "/home/user/nix").inputs.nixpkgs.outPath'
 // nix-env -f /nix/store/sjmq1gphj1arbzf4aqqnygd9pf4hkfkf-source -qa --json > packages.json
-fn setupupdatecache() -> Result<(), Box<dyn Error>> {
+fn setupupdatecache() -> Result<(), Box<dyn Error + Send + Sync>> {
     info!("Setting up update cache");
     let output = Command::new("nix-instantiate")
         .arg("--eval")
@@ -455,7 +803,7 @@ fn setupupdatecache() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn setupnewestver() -> Result<(), Box<dyn Error>> {
+fn setupnewestver() -> Result<(), Box<dyn Error + Send + Sync>> {
     let output = Command::new("nix-instantiate")
         .arg("--eval")
         .arg("-E")
@@ -491,56 +839,705 @@ fn setupnewestver() -> Result<(), Box<dyn Error>> {
             let newver = latest;
             info!("OLD: {}, != NEW: {}", oldver, newver);
         }
-        if Path::new(format!("{}/newver.txt", &cachedir).as_str()).exists() {
-            fs::remove_file(format!("{}/newver.txt", &cachedir).as_str())?;
-        }
+        // removeifexists rather than exists()-then-remove_file(): a newver.txt
+        // that is already gone is treated as removed instead of as an error.
+        removeifexists(format!("{}/newver.txt", &cachedir).as_str())?;
         let mut newver = fs::File::create(format!("{}/newver.txt", &cachedir))?;
         newver.write_all(latest.as_bytes())?;
     }
     Ok(())
 }
 
-fn dlfile(url: &str, path: &str) -> Result<(), Box<dyn Error>> {
-    trace!("Downloading {}", url);
-    let response = reqwest::blocking::get(url)?;
-    if response.status().is_success() {
-        let cachedir = format!("{}/.cache/nix-software-center", env::var("HOME")?);
-        if !Path::new(&cachedir).exists() {
-            fs::create_dir_all(&cachedir).expect("Failed to create cache directory");
-        }
+const DLFILE_MAX_ATTEMPTS: u32 = 3;
+const DLFILE_BASE_BACKOFF: Duration = Duration::from_millis(500);
 
-        let dst: Vec<u8> = response.bytes()?.to_vec();
-        {
-            let mut file = File::create(path)?;
-            let mut reader = brotli::Decompressor::new(
-                dst.as_slice(),
-                4096, // buffer size
+// Compression an artifact is published under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompressionFormat {
+    Brotli,
+    Zstd,
+    Gzip,
+    PlainJson,
+}
+
+// Tries a `.br` URL as `.zst` first, falling back to the original `.br` URL.
+fn candidateurls(url: &str) -> Vec<String> {
+    match url.strip_suffix(".br") {
+        Some(stem) => vec![format!("{}.zst", stem), url.to_string()],
+        None => vec![url.to_string()],
+    }
+}
+
+fn backoffdelay(attempt: u32) -> Duration {
+    DLFILE_BASE_BACKOFF * 2u32.pow(attempt.saturating_sub(1))
+}
+
+// Retries transport errors, 5xx responses, and checksum mismatches with
+// exponential backoff. A candidate that 404s is not retried on later
+// attempts, so a mirror without `.zst` artifacts only pays for that probe
+// once per call.
+fn dlfile(url: &str, path: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let candidates = candidateurls(url);
+    let mut exhausted = vec![false; candidates.len()];
+    let mut lasterr: Option<Box<dyn Error + Send + Sync>> = None;
+    for attempt in 0..DLFILE_MAX_ATTEMPTS {
+        if exhausted.iter().all(|&x| x) {
+            break;
+        }
+        if attempt > 0 {
+            let backoff = backoffdelay(attempt);
+            warn!(
+                "Retrying download of {} (attempt {}/{}) after {:?}",
+                url,
+                attempt + 1,
+                DLFILE_MAX_ATTEMPTS,
+                backoff
             );
-            let mut buf = [0u8; 4096];
-            loop {
-                match reader.read(&mut buf[..]) {
-                    Err(e) => {
-                        if let std::io::ErrorKind::Interrupted = e.kind() {
-                            continue;
-                        }
-                        return Err(Box::new(e));
-                    }
-                    Ok(size) => {
-                        if size == 0 {
-                            break;
-                        }
-                        file.write_all(&buf[..size])?
+            std::thread::sleep(backoff);
+        }
+        for (i, candidate) in candidates.iter().enumerate() {
+            if exhausted[i] {
+                continue;
+            }
+            match dlfileattempt(candidate, path) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    error!("Download of {} failed: {}", candidate, e);
+                    if e.notfound {
+                        exhausted[i] = true;
                     }
+                    lasterr = Some(e.source);
                 }
             }
         }
+    }
+    Err(lasterr.unwrap_or_else(|| "Download failed for an unknown reason".into()))
+}
+
+// Compression implied by a URL's suffix alone, with no network access.
+fn compressionfromurlsuffix(url: &str) -> Option<CompressionFormat> {
+    if url.ends_with(".zst") {
+        Some(CompressionFormat::Zstd)
+    } else if url.ends_with(".gz") {
+        Some(CompressionFormat::Gzip)
+    } else if url.ends_with(".br") {
+        Some(CompressionFormat::Brotli)
+    } else if url.ends_with(".json") {
+        Some(CompressionFormat::PlainJson)
     } else {
-        error!("Failed to download {}", url);
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Failed to download file",
-        )))
+        None
+    }
+}
+
+// Falls back to the Content-Encoding/Content-Type headers, then to brotli
+// (the long-standing NixOS channel default) when the URL suffix doesn't say.
+fn detectcompression(url: &str, response: &reqwest::blocking::Response) -> CompressionFormat {
+    if let Some(format) = compressionfromurlsuffix(url) {
+        return format;
+    }
+
+    let headers = response.headers();
+    if let Some(encoding) = headers
+        .get(reqwest::header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    {
+        match encoding {
+            "zstd" => return CompressionFormat::Zstd,
+            "gzip" => return CompressionFormat::Gzip,
+            "br" => return CompressionFormat::Brotli,
+            _ => {}
+        }
+    }
+    if let Some(ctype) = headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    {
+        if ctype.contains("json") {
+            return CompressionFormat::PlainJson;
+        }
+    }
+
+    CompressionFormat::Brotli
+}
+
+// Streams every byte `reader` produces into `writer`, retrying on `Interrupted`.
+fn streamdecoded(
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut buf = [0u8; 4096];
+    loop {
+        match reader.read(&mut buf[..]) {
+            Err(e) => {
+                if let std::io::ErrorKind::Interrupted = e.kind() {
+                    continue;
+                }
+                return Err(Box::new(e));
+            }
+            Ok(0) => return Ok(()),
+            Ok(size) => writer.write_all(&buf[..size])?,
+        }
+    }
+}
+
+fn decompressstream(
+    format: CompressionFormat,
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match format {
+        CompressionFormat::Brotli => {
+            let mut decoder = brotli::Decompressor::new(reader, 4096);
+            streamdecoded(&mut decoder, writer)?;
+        }
+        CompressionFormat::Zstd => {
+            let mut decoder = zstd::stream::Decoder::new(reader)?;
+            streamdecoded(&mut decoder, writer)?;
+        }
+        CompressionFormat::Gzip => {
+            let mut decoder = flate2::read::GzDecoder::new(reader);
+            streamdecoded(&mut decoder, writer)?;
+        }
+        CompressionFormat::PlainJson => {
+            streamdecoded(reader, writer)?;
+        }
+    }
+    Ok(())
+}
+
+// Wraps a Read, feeding every byte that passes through into a running
+// SHA-256 hash so a download can be verified without buffering it twice.
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut Sha256,
+}
+
+impl<'a, R: Read> Read for HashingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+// A 404 means the artifact plainly doesn't exist at that URL, so `dlfile`
+// stops retrying that candidate instead of re-probing a URL that will
+// never succeed.
+#[derive(Debug)]
+struct DlAttemptError {
+    notfound: bool,
+    source: Box<dyn Error + Send + Sync>,
+}
+
+impl std::fmt::Display for DlAttemptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Error for DlAttemptError {}
+
+impl From<Box<dyn Error + Send + Sync>> for DlAttemptError {
+    fn from(source: Box<dyn Error + Send + Sync>) -> Self {
+        DlAttemptError {
+            notfound: false,
+            source,
+        }
+    }
+}
+
+impl From<String> for DlAttemptError {
+    fn from(s: String) -> Self {
+        DlAttemptError {
+            notfound: false,
+            source: s.into(),
+        }
+    }
+}
+
+impl From<std::io::Error> for DlAttemptError {
+    fn from(e: std::io::Error) -> Self {
+        DlAttemptError {
+            notfound: false,
+            source: Box::new(e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for DlAttemptError {
+    fn from(e: reqwest::Error) -> Self {
+        DlAttemptError {
+            notfound: false,
+            source: Box::new(e),
+        }
+    }
+}
+
+impl From<std::env::VarError> for DlAttemptError {
+    fn from(e: std::env::VarError) -> Self {
+        DlAttemptError {
+            notfound: false,
+            source: Box::new(e),
+        }
+    }
+}
+
+// Best-effort: a `.sha256` sidecar isn't a documented convention for every
+// NixOS channel mirror, so a miss here is expected rather than exceptional.
+// Log it instead of swallowing it, since otherwise there's no way to tell
+// that checksum verification is silently inert for a given URL — the
+// write-to-tmp-then-rename in dlfileattempt still protects against a
+// truncated/interrupted transfer regardless of whether a sidecar exists.
+fn fetchsha256(url: &str) -> Option<String> {
+    let hashurl = format!("{}.sha256", url);
+    let response = match reqwest::blocking::get(&hashurl) {
+        Ok(r) => r,
+        Err(e) => {
+            debug!("No SHA-256 sidecar reachable at {}: {}", hashurl, e);
+            return None;
+        }
+    };
+    if !response.status().is_success() {
+        debug!(
+            "No SHA-256 sidecar at {} ({}); skipping checksum verification for {}",
+            hashurl,
+            response.status(),
+            url
+        );
+        return None;
+    }
+    let text = match response.text() {
+        Ok(t) => t,
+        Err(e) => {
+            debug!("Failed to read SHA-256 sidecar body at {}: {}", hashurl, e);
+            return None;
+        }
+    };
+    let hash = text.split_whitespace().next().map(|s| s.to_lowercase());
+    match hash {
+        Some(h) if !h.is_empty() => Some(h),
+        _ => {
+            debug!(
+                "SHA-256 sidecar at {} was empty; skipping checksum verification for {}",
+                hashurl, url
+            );
+            None
+        }
     }
+}
+
+fn dlfileattempt(url: &str, path: &str) -> Result<(), DlAttemptError> {
+    trace!("Downloading {}", url);
+    let mut response = reqwest::blocking::get(url)?;
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Err(DlAttemptError {
+            notfound: true,
+            source: format!("{} does not exist ({})", url, status).into(),
+        });
+    }
+    if status.is_server_error() {
+        return Err(format!("Server error {} while downloading {}", status, url).into());
+    }
+    if !status.is_success() {
+        return Err(format!("Failed to download {} ({})", url, status).into());
+    }
+    let format = detectcompression(url, &response);
+    let expectedhash = fetchsha256(url);
+
+    let cachedir = format!("{}/.cache/nix-software-center", env::var("HOME")?);
+    if !Path::new(&cachedir).exists() {
+        fs::create_dir_all(&cachedir).expect("Failed to create cache directory");
+    }
+
+    let tmppath = format!("{}.tmp", path);
+    let mut hasher = Sha256::new();
+    {
+        let mut file = File::create(&tmppath)?;
+        let mut hashing = HashingReader {
+            inner: &mut response,
+            hasher: &mut hasher,
+        };
+        if let Err(e) = decompressstream(format, &mut hashing, &mut file) {
+            let _ = fs::remove_file(&tmppath);
+            return Err(e.into());
+        }
+    }
+
+    if let Some(expected) = expectedhash {
+        let actual = format!("{:x}", hasher.finalize());
+        if expected != actual {
+            let _ = fs::remove_file(&tmppath);
+            return Err(format!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                url, expected, actual
+            )
+            .into());
+        }
+    }
+
+    fs::rename(&tmppath, path)?;
+
     trace!("Finished downloading {} -> {}", url, path);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn partitionbyfreshness_reuses_entries_within_ttl() {
+        let mut cache = HashMap::new();
+        cache.insert(
+            "hello".to_string(),
+            NarinfoCacheEntry {
+                availability: SubstituterAvailability::Cached,
+                checkedat: 1_000,
+            },
+        );
+        let now = 1_000 + NARINFO_CACHE_TTL_SECS - 1;
+        let attrs = vec!["hello".to_string()];
+        let (results, tolookup) = partitionbyfreshness(&attrs, &cache, now);
+        assert_eq!(
+            results.get("hello"),
+            Some(&SubstituterAvailability::Cached)
+        );
+        assert!(tolookup.is_empty());
+    }
+
+    #[test]
+    fn partitionbyfreshness_relooks_up_expired_entries() {
+        let mut cache = HashMap::new();
+        cache.insert(
+            "hello".to_string(),
+            NarinfoCacheEntry {
+                availability: SubstituterAvailability::Cached,
+                checkedat: 1_000,
+            },
+        );
+        let now = 1_000 + NARINFO_CACHE_TTL_SECS;
+        let attrs = vec!["hello".to_string()];
+        let (results, tolookup) = partitionbyfreshness(&attrs, &cache, now);
+        assert!(results.is_empty());
+        assert_eq!(tolookup, vec!["hello".to_string()]);
+    }
+
+    #[test]
+    fn partitionbyfreshness_looks_up_attrs_missing_from_cache() {
+        let cache = HashMap::new();
+        let attrs = vec!["hello".to_string()];
+        let (results, tolookup) = partitionbyfreshness(&attrs, &cache, 1_000);
+        assert!(results.is_empty());
+        assert_eq!(tolookup, vec!["hello".to_string()]);
+    }
+
+    // Regression test for storepathhash wrongly passing `--derivation` to
+    // `nix path-info`, which reported the .drv's own hash instead of the
+    // output store path's, making checksubstituter 404 for everything.
+    // Requires a working `nix-instantiate`/`nix-store` and nixpkgs, so it
+    // doesn't run in CI by default.
+    #[test]
+    #[ignore]
+    fn storepathhash_resolves_output_not_derivation() {
+        let hash = storepathhash("hello").expect("failed to hash hello's output path");
+        assert_eq!(hash.len(), 32);
+        assert!(hash.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+
+    #[test]
+    fn backoffdelay_doubles_each_attempt() {
+        assert_eq!(backoffdelay(1), Duration::from_millis(500));
+        assert_eq!(backoffdelay(2), Duration::from_millis(1000));
+        assert_eq!(backoffdelay(3), Duration::from_millis(2000));
+    }
+
+    #[test]
+    fn hashingreader_hashes_what_it_reads() {
+        let data = b"nix-software-center".to_vec();
+        let mut hasher = Sha256::new();
+        let mut hashing = HashingReader {
+            inner: Cursor::new(&data),
+            hasher: &mut hasher,
+        };
+        let mut out = Vec::new();
+        hashing.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+
+        let mut expected = Sha256::new();
+        expected.update(&data);
+        assert_eq!(hasher.finalize(), expected.finalize());
+    }
+
+    #[test]
+    fn candidateurls_tries_zst_before_br() {
+        assert_eq!(
+            candidateurls("https://example.com/packages.json.br"),
+            vec![
+                "https://example.com/packages.json.zst".to_string(),
+                "https://example.com/packages.json.br".to_string(),
+            ]
+        );
+        assert_eq!(
+            candidateurls("https://example.com/packages.json"),
+            vec!["https://example.com/packages.json".to_string()]
+        );
+    }
+
+    #[test]
+    fn compressionfromurlsuffix_matches_known_extensions() {
+        assert_eq!(
+            compressionfromurlsuffix("https://example.com/packages.json.zst"),
+            Some(CompressionFormat::Zstd)
+        );
+        assert_eq!(
+            compressionfromurlsuffix("https://example.com/packages.json.gz"),
+            Some(CompressionFormat::Gzip)
+        );
+        assert_eq!(
+            compressionfromurlsuffix("https://example.com/packages.json.br"),
+            Some(CompressionFormat::Brotli)
+        );
+        assert_eq!(
+            compressionfromurlsuffix("https://example.com/packages.json"),
+            Some(CompressionFormat::PlainJson)
+        );
+        assert_eq!(
+            compressionfromurlsuffix("https://example.com/packages"),
+            None
+        );
+    }
+
+    #[test]
+    fn decompressstream_roundtrips_plainjson() {
+        let data = b"{\"hello\":\"world\"}".to_vec();
+        let mut out = Vec::new();
+        decompressstream(CompressionFormat::PlainJson, &mut Cursor::new(&data), &mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn decompressstream_roundtrips_gzip() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let data = b"nix-software-center gzip roundtrip".to_vec();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut out = Vec::new();
+        decompressstream(CompressionFormat::Gzip, &mut Cursor::new(&compressed), &mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn decompressstream_roundtrips_zstd() {
+        let data = b"nix-software-center zstd roundtrip".to_vec();
+        let compressed = zstd::stream::encode_all(Cursor::new(&data), 0).unwrap();
+
+        let mut out = Vec::new();
+        decompressstream(CompressionFormat::Zstd, &mut Cursor::new(&compressed), &mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn decompressstream_roundtrips_brotli() {
+        let data = b"nix-software-center brotli roundtrip".to_vec();
+        let mut compressed = Vec::new();
+        {
+            let mut encoder =
+                brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+            encoder.write_all(&data).unwrap();
+        }
+
+        let mut out = Vec::new();
+        decompressstream(CompressionFormat::Brotli, &mut Cursor::new(&compressed), &mut out)
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    // (status, body) pairs served, in order, to requests for one path.
+    type MockResponses = Vec<(u16, Vec<u8>)>;
+
+    // A tiny single-purpose HTTP/1.1 server so dlfile's retry/backoff/
+    // exhaustion/checksum state machine can be driven end to end instead of
+    // only through its leaf helpers: reqwest::blocking::get talks to it like
+    // any other server, and each path gets its own queue of canned
+    // (status, body) responses, consumed in request order.
+    fn spawnmockserver(routes: Vec<(&str, MockResponses)>) -> (String, std::thread::JoinHandle<()>) {
+        use std::io::BufRead;
+
+        let mut queues: HashMap<String, std::collections::VecDeque<(u16, Vec<u8>)>> =
+            HashMap::new();
+        let mut totalrequests = 0usize;
+        for (path, responses) in routes {
+            totalrequests += responses.len();
+            queues.insert(path.to_string(), responses.into());
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let baseurl = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle = std::thread::spawn(move || {
+            for _ in 0..totalrequests {
+                let (mut stream, _) = match listener.accept() {
+                    Ok(x) => x,
+                    Err(_) => return,
+                };
+
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut requestline = String::new();
+                if reader.read_line(&mut requestline).unwrap_or(0) == 0 {
+                    continue;
+                }
+                let path = requestline
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or("/")
+                    .to_string();
+                loop {
+                    let mut headerline = String::new();
+                    match reader.read_line(&mut headerline) {
+                        Ok(0) => break,
+                        Ok(_) if headerline == "\r\n" || headerline == "\n" => break,
+                        Ok(_) => continue,
+                        Err(_) => break,
+                    }
+                }
+
+                let (status, body) = queues
+                    .get_mut(&path)
+                    .and_then(|q| q.pop_front())
+                    .unwrap_or((404, Vec::new()));
+                let statustext = match status {
+                    200 => "OK",
+                    404 => "Not Found",
+                    _ => "Internal Server Error",
+                };
+                let header = format!(
+                    "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    statustext,
+                    body.len()
+                );
+                let _ = stream.write_all(header.as_bytes());
+                let _ = stream.write_all(&body);
+                let _ = stream.flush();
+            }
+        });
+
+        (baseurl, handle)
+    }
+
+    fn brotlicompress(data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+            encoder.write_all(data).unwrap();
+        }
+        compressed
+    }
+
+    #[test]
+    fn dlfile_retries_after_server_error_then_succeeds() {
+        let body = b"{\"ok\":true}".to_vec();
+        let (baseurl, handle) = spawnmockserver(vec![
+            (
+                "/packages.json",
+                vec![(500, Vec::new()), (200, body.clone())],
+            ),
+            ("/packages.json.sha256", vec![(404, Vec::new())]),
+        ]);
+
+        let dir = std::env::temp_dir().join(format!(
+            "nsc-dlfile-test-{:?}",
+            std::thread::current().id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let outpath = dir.join("packages.json");
+
+        dlfile(
+            &format!("{}/packages.json", baseurl),
+            outpath.to_str().unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(fs::read(&outpath).unwrap(), body);
+        let _ = fs::remove_dir_all(&dir);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn dlfile_404_on_one_candidate_does_not_block_the_other() {
+        let body = b"{\"zstd-unavailable\":true}".to_vec();
+        let compressed = brotlicompress(&body);
+        let (baseurl, handle) = spawnmockserver(vec![
+            ("/packages.json.zst", vec![(404, Vec::new())]),
+            (
+                "/packages.json.br",
+                vec![(500, Vec::new()), (200, compressed)],
+            ),
+            ("/packages.json.br.sha256", vec![(404, Vec::new())]),
+        ]);
+
+        let dir = std::env::temp_dir().join(format!(
+            "nsc-dlfile-test-candidates-{:?}",
+            std::thread::current().id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let outpath = dir.join("packages.json");
+
+        dlfile(
+            &format!("{}/packages.json.br", baseurl),
+            outpath.to_str().unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(fs::read(&outpath).unwrap(), body);
+        let _ = fs::remove_dir_all(&dir);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn dlfile_checksum_mismatch_never_clobbers_the_good_cache_file() {
+        let body = b"{\"fresh\":true}".to_vec();
+        let wronghash = "0".repeat(64);
+        let (baseurl, handle) = spawnmockserver(vec![
+            (
+                "/packages.json",
+                vec![(200, body.clone()), (200, body.clone()), (200, body)],
+            ),
+            (
+                "/packages.json.sha256",
+                vec![
+                    (200, wronghash.clone().into_bytes()),
+                    (200, wronghash.clone().into_bytes()),
+                    (200, wronghash.into_bytes()),
+                ],
+            ),
+        ]);
+
+        let dir = std::env::temp_dir().join(format!(
+            "nsc-dlfile-test-mismatch-{:?}",
+            std::thread::current().id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let outpath = dir.join("packages.json");
+        let goodcontent = b"previously-downloaded-good-cache".to_vec();
+        fs::write(&outpath, &goodcontent).unwrap();
+
+        let result = dlfile(
+            &format!("{}/packages.json", baseurl),
+            outpath.to_str().unwrap(),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(fs::read(&outpath).unwrap(), goodcontent);
+        let tmppath = format!("{}.tmp", outpath.to_str().unwrap());
+        assert!(!Path::new(&tmppath).exists());
+        let _ = fs::remove_dir_all(&dir);
+        handle.join().unwrap();
+    }
+}